@@ -1,39 +1,216 @@
 use chrono::{DateTime, FixedOffset, NaiveDate};
-use rets_expression::{Engine, EvaluateContext, Expression};
+use rets_expression::{Engine, EvaluateContext, Expression, FieldNode, FunctionNode, Visitor};
 use std::borrow::Cow;
 
 mod wasm;
 
-/// Take the input, use the [rets_expression] interface to evaluate, and return a result.
+/// A single record to evaluate the expression against.
+pub(crate) struct Row {
+    /// JSON representing the data to be evaluated
+    pub value: serde_json::Value,
+    /// JSON representing the data as it was previously, used in expressions like `[LAST FieldName]`
+    pub previous_value: Option<serde_json::Value>,
+}
+
+/// Build the [Engine] used to evaluate an expression: the built-in `NOW`/`TODAY` intrinsics, plus
+/// a [HostFunction] for every host-supplied name the caller declared.
+fn build_engine(functions: Vec<String>) -> Engine<TimeState> {
+    let mut engine = Engine::default()
+        .with_function("NOW", Box::new(NowFunction))
+        .with_function("TODAY", Box::new(TodayFunction));
+    for name in functions {
+        engine = engine.with_function(name.clone(), Box::new(HostFunction::new(name)));
+    }
+    engine
+}
+
+/// Parse an IANA timezone name, if one was supplied.
+fn parse_timezone(timezone: Option<String>) -> Result<Option<chrono_tz::Tz>, String> {
+    match timezone {
+        Some(name) => Ok(Some(
+            name.parse::<chrono_tz::Tz>()
+                .map_err(|_| format!("Unrecognized timezone: `{name}`"))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Parse `timezone` and build the [TimeState] `.NOW.`/`.TODAY.` are evaluated against.
+///
+/// `today` is only a fallback for when no `timezone` is supplied (see [TimeState::today]), so it's
+/// only required in that case: at least one of the two must be present, or there's no way to know
+/// what day `.TODAY.` means.
+fn resolve_time_state(
+    now: DateTime<FixedOffset>,
+    today: Option<NaiveDate>,
+    timezone: Option<String>,
+) -> Result<TimeState, String> {
+    let timezone = parse_timezone(timezone)?;
+    if timezone.is_none() && today.is_none() {
+        return Err("Either `timezone` or `date` must be provided".to_string());
+    }
+
+    Ok(TimeState {
+        now,
+        today: today.unwrap_or(NaiveDate::MIN),
+        timezone,
+    })
+}
+
+/// Take the input, use the [rets_expression] interface to evaluate, and return a result for every
+/// row.
+///
+/// The expression is parsed and the [Engine] is built exactly once and then reused across every
+/// row, so callers that need to apply the same expression to many records only pay the parsing
+/// cost a single time. A failure to parse the expression itself short-circuits the whole batch; a
+/// failure to evaluate a single row is reported for that row only.
 fn evaluate_expression(
     expression: String,
-    value: serde_json::Value,
-    previous_value: Option<serde_json::Value>,
+    rows: Vec<Row>,
+    functions: Vec<String>,
+    timezone: Option<String>,
     now: DateTime<FixedOffset>,
-    today: NaiveDate,
-) -> Result<serde_json::Value, String> {
+    today: Option<NaiveDate>,
+) -> Result<Vec<Result<serde_json::Value, String>>, String> {
     // Parse the RCP19 expression.
     let expression = expression
         .parse::<Expression>()
         .map_err(|err| format!("Failed to parse expression: {err}"))?;
 
-    // Set up the evaluation engine
-    let engine = Engine::default()
-        .with_function("NOW", Box::new(NowFunction))
-        .with_function("TODAY", Box::new(TodayFunction));
+    // Set up the evaluation engine, including the built-in intrinsics and any host-supplied
+    // functions the caller declared.
+    let engine = build_engine(functions);
+
+    // Parse the timezone (if any) and resolve the time state the engine evaluates against.
+    let state = resolve_time_state(now, today, timezone)?;
+
+    // Evaluate the expression against every row, isolating failures so that one bad record
+    // doesn't fail the rest of the batch.
+    let results = rows
+        .into_iter()
+        .map(|row| {
+            HOST_FUNCTION_ERROR.with(|cell| *cell.borrow_mut() = None);
+            let context = EvaluateContext::new_with_state(&engine, &row.value, state)
+                .set_previous(row.previous_value.as_ref());
+
+            expression
+                .apply(context)
+                .map(|value| value.into_owned())
+                .map_err(|err| format!("Failed to evaluate expression: {}", describe_error(err)))
+        })
+        .collect();
 
-    // Set up some context when running the engine
-    let state = TimeState { now, today };
-    let context = EvaluateContext::new_with_state(&engine, &value, state)
+    Ok(results)
+}
+
+/// A parsed expression together with the [Engine] it should be evaluated with, cached so that
+/// repeated evaluations (e.g. on every keystroke while a user edits a form) don't pay the parsing
+/// cost again.
+pub(crate) struct Compiled {
+    expression: Expression,
+    engine: Engine<TimeState>,
+}
+
+/// Parse `expression` and build its [Engine] exactly once, registering the same built-in
+/// intrinsics and host-supplied `functions` that [evaluate_expression] would, and returning a
+/// [Compiled] handle that can be evaluated many times via [evaluate_compiled].
+pub(crate) fn compile_expression(
+    expression: String,
+    functions: Vec<String>,
+) -> Result<Compiled, String> {
+    let expression = expression
+        .parse::<Expression>()
+        .map_err(|err| format!("Failed to parse expression: {err}"))?;
+
+    let engine = build_engine(functions);
+
+    Ok(Compiled { expression, engine })
+}
+
+/// Evaluate a previously-[compile_expression]d expression against a single row, without
+/// re-parsing it or rebuilding its [Engine].
+pub(crate) fn evaluate_compiled(
+    compiled: &Compiled,
+    value: serde_json::Value,
+    previous_value: Option<serde_json::Value>,
+    timezone: Option<String>,
+    now: DateTime<FixedOffset>,
+    today: Option<NaiveDate>,
+) -> Result<serde_json::Value, String> {
+    let state = resolve_time_state(now, today, timezone)?;
+    HOST_FUNCTION_ERROR.with(|cell| *cell.borrow_mut() = None);
+    let context = EvaluateContext::new_with_state(&compiled.engine, &value, state)
         .set_previous(previous_value.as_ref());
 
-    // Evaluate the expression
-    let value = expression
+    compiled
+        .expression
         .apply(context)
-        .map_err(|err| format!("Failed to evaluate expression: {err}"))?;
+        .map(|value| value.into_owned())
+        .map_err(|err| format!("Failed to evaluate expression: {}", describe_error(err)))
+}
 
-    // And return the JSON that came out of the engine
-    Ok(value.into_owned())
+/// Everything a host needs to know to decide when an expression must be re-run, without running
+/// it: which fields it reads, and whether it needs the time state or the previous value at all.
+pub(crate) struct Capabilities {
+    pub fields: Vec<String>,
+    pub uses_previous: bool,
+    pub uses_now: bool,
+    pub uses_today: bool,
+}
+
+/// Parse `expression` and report the fields and intrinsics it depends on, without evaluating it
+/// against any data.
+///
+/// This walks the parsed AST rather than scanning the source text, so a string literal that
+/// happens to mention `.NOW.` or a field name (e.g. `IIF(Notes = "Call .NOW.", 1, 0)`) isn't
+/// mistaken for an actual reference, and a call to a built-in or host-supplied function isn't
+/// mistaken for a field.
+///
+/// This lets a host build reactive recomputation: only re-run an expression when one of its
+/// referenced fields changes, and know up front whether it must supply `previousValue` or the time
+/// state at all.
+pub(crate) fn analyze_expression(expression: String) -> Result<Capabilities, String> {
+    let mut expression = expression
+        .parse::<Expression>()
+        .map_err(|err| format!("Failed to parse expression: {err}"))?;
+
+    let mut capabilities = Capabilities {
+        fields: Vec::new(),
+        uses_previous: false,
+        uses_now: false,
+        uses_today: false,
+    };
+    expression.accept(&mut CapabilitiesVisitor {
+        capabilities: &mut capabilities,
+    });
+    capabilities.fields.sort();
+    capabilities.fields.dedup();
+
+    Ok(capabilities)
+}
+
+/// A [Visitor] that records every field the expression reads and whether it calls `NOW`/`TODAY` or
+/// references a previous value via `LAST`, by walking the parsed AST rather than its source text.
+struct CapabilitiesVisitor<'a> {
+    capabilities: &'a mut Capabilities,
+}
+
+impl Visitor for CapabilitiesVisitor<'_> {
+    fn visit_field_node(&mut self, node: &mut FieldNode) {
+        self.capabilities.fields.push(node.name.clone());
+    }
+
+    fn visit_last_field_node(&mut self, _node: &mut rets_expression::LastFieldNode) {
+        self.capabilities.uses_previous = true;
+    }
+
+    fn visit_function_node_in(&mut self, node: &mut FunctionNode) {
+        match node.name.as_str() {
+            "NOW" => self.capabilities.uses_now = true,
+            "TODAY" => self.capabilities.uses_today = true,
+            _ => {}
+        }
+    }
 }
 
 /// State provided to the [rets_expression::Engine]
@@ -44,12 +221,17 @@ fn evaluate_expression(
 #[derive(Copy, Clone)]
 struct TimeState {
     now: DateTime<chrono::FixedOffset>,
+    /// Fallback date to use for `.TODAY.` when no `timezone` was supplied.
     today: chrono::NaiveDate,
+    /// The IANA timezone `.NOW.`/`.TODAY.` should be expressed in, if the caller supplied one.
+    timezone: Option<chrono_tz::Tz>,
 }
 
 /// The function that handles calls to `.TODAY.`
 ///
-/// When called, this creates a JSON string in `1985-04-21` format.
+/// When called, this creates a JSON string in `1985-04-21` format. If a `timezone` was supplied,
+/// the date is derived from `now` converted into that zone; otherwise it falls back to the
+/// explicit `date` the caller sent in.
 struct TodayFunction;
 
 impl rets_expression::function::Function<TimeState> for TodayFunction {
@@ -59,15 +241,20 @@ impl rets_expression::function::Function<TimeState> for TodayFunction {
         _input: Vec<Cow<'json, serde_json::Value>>,
     ) -> Result<Cow<'json, serde_json::Value>, rets_expression::function::FunctionError> {
         let state = context.state();
+        let today = match state.timezone {
+            Some(tz) => state.now.with_timezone(&tz).date_naive(),
+            None => state.today,
+        };
         Ok(Cow::Owned(serde_json::Value::String(
-            state.today.format("%Y-%m-%d").to_string(),
+            today.format("%Y-%m-%d").to_string(),
         )))
     }
 }
 
 /// The function that handles calls to `.NOW.`
 ///
-/// When called, this creates a JSON string in `1985-04-21T01:35:57Z` format.
+/// When called, this creates a JSON string in `1985-04-21T01:35:57.000Z` format. If a `timezone`
+/// was supplied, the timestamp is expressed in that zone instead of `now`'s original offset.
 struct NowFunction;
 
 impl rets_expression::function::Function<TimeState> for NowFunction {
@@ -77,10 +264,127 @@ impl rets_expression::function::Function<TimeState> for NowFunction {
         _input: Vec<Cow<'json, serde_json::Value>>,
     ) -> Result<Cow<'json, serde_json::Value>, rets_expression::function::FunctionError> {
         let state = context.state();
-        Ok(Cow::Owned(serde_json::Value::String(
-            state
+        let formatted = match state.timezone {
+            Some(tz) => state
                 .now
+                .with_timezone(&tz)
                 .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-        )))
+            None => state
+                .now
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        };
+        Ok(Cow::Owned(serde_json::Value::String(formatted)))
+    }
+}
+
+/// A function implemented in Javascript and registered for this evaluation, so that RCP19
+/// expressions can call out to host-provided business logic (geocoding, rate tables, MLS status
+/// maps, and the like) without the wasm module knowing anything about it.
+///
+/// Calling one of these serializes its arguments to a JSON array and calls back into Javascript
+/// via [wasm::invoke_host_function]; see that function for the wire format.
+struct HostFunction {
+    name: String,
+}
+
+impl HostFunction {
+    fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+impl rets_expression::function::Function<TimeState> for HostFunction {
+    fn evaluate<'json>(
+        &self,
+        _context: rets_expression::function::FunctionContext<'_, TimeState>,
+        input: Vec<Cow<'json, serde_json::Value>>,
+    ) -> Result<Cow<'json, serde_json::Value>, rets_expression::function::FunctionError> {
+        let args: Vec<&serde_json::Value> = input.iter().map(|value| value.as_ref()).collect();
+        let args_json = serde_json::to_string(&args).map_err(|err| {
+            stash_host_function_error(format!(
+                "Failed to serialize arguments for host function `{}`: {err}",
+                self.name
+            ))
+        })?;
+
+        let result = wasm::invoke_host_function(&self.name, &args_json)
+            .map_err(stash_host_function_error)?;
+
+        Ok(Cow::Owned(result))
+    }
+}
+
+std::thread_local! {
+    /// The message behind the most recent [HostFunction] failure, since
+    /// [rets_expression::function::FunctionError] has no variant that can carry one of its own.
+    /// [describe_error] reads (and clears) this after a failed [Expression::apply] to surface it
+    /// instead of a generic message.
+    static HOST_FUNCTION_ERROR: std::cell::RefCell<Option<String>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Record `message` as the reason the in-flight host function call failed, and return the
+/// [rets_expression::function::FunctionError] variant to hand back to the engine.
+fn stash_host_function_error(message: String) -> rets_expression::function::FunctionError {
+    HOST_FUNCTION_ERROR.with(|cell| *cell.borrow_mut() = Some(message));
+    rets_expression::function::FunctionError::InvalidType
+}
+
+/// Describe why [Expression::apply] failed: the message stashed by [stash_host_function_error], if
+/// the failure came from a host function call, or a debug-formatted fallback otherwise.
+fn describe_error(err: rets_expression::Error) -> String {
+    HOST_FUNCTION_ERROR
+        .with(|cell| cell.borrow_mut().take())
+        .unwrap_or_else(|| format!("{err:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analyze_expression_collects_fields() {
+        let capabilities = analyze_expression("Field1 = Field2".to_string()).unwrap();
+        assert_eq!(capabilities.fields, vec!["Field1", "Field2"]);
+        assert!(!capabilities.uses_previous);
+        assert!(!capabilities.uses_now);
+        assert!(!capabilities.uses_today);
+    }
+
+    #[test]
+    fn analyze_expression_detects_now_and_today() {
+        let capabilities = analyze_expression("IIF(.NOW. = .TODAY., 1, 0)".to_string()).unwrap();
+        assert!(capabilities.fields.is_empty());
+        assert!(capabilities.uses_now);
+        assert!(capabilities.uses_today);
+    }
+
+    #[test]
+    fn analyze_expression_detects_last() {
+        let capabilities = analyze_expression("LAST MlsStatus".to_string()).unwrap();
+        assert!(capabilities.uses_previous);
+    }
+
+    #[test]
+    fn analyze_expression_ignores_string_literals_that_look_like_syntax() {
+        // A string literal that happens to mention `.NOW.`/a field name must not be mistaken for
+        // an actual reference to it; only the parsed AST should be consulted, not the source text.
+        let capabilities =
+            analyze_expression(r#"IIF(Notes = "Call .NOW. about MlsStatus", 1, 0)"#.to_string())
+                .unwrap();
+        assert_eq!(capabilities.fields, vec!["Notes"]);
+        assert!(!capabilities.uses_now);
+    }
+
+    #[test]
+    fn analyze_expression_does_not_mistake_function_calls_for_fields() {
+        let capabilities = analyze_expression("YEAR(.NOW.) = 2024".to_string()).unwrap();
+        assert!(capabilities.fields.is_empty());
+        assert!(capabilities.uses_now);
+    }
+
+    #[test]
+    fn analyze_expression_rejects_invalid_syntax() {
+        assert!(analyze_expression("(".to_string()).is_err());
     }
 }
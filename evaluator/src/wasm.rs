@@ -32,36 +32,64 @@
 use chrono::{DateTime, FixedOffset, NaiveDate};
 use serde::{Deserialize, Serialize};
 
+/// Which wire format a request/response pair is encoded with.
+#[derive(Copy, Clone)]
+enum Codec {
+    /// UTF-8 JSON text, used by [run].
+    Json,
+    /// MessagePack, used by [run_msgpack]. Skips both the UTF-8 validation step (meaningless for
+    /// binary input) and JSON text parsing, which matters for large `value` documents.
+    MessagePack,
+}
+
 /// The top-level module handler
 ///
 /// This is not exported by the WebAssembly module, but it's the top-level function doing some of
 /// the marshalling.
-fn top_level(input: &[u8]) -> ResponseJson {
-    // Check that it's a valid UTF-8 string.
-    let input = match std::str::from_utf8(input) {
-        Ok(input) => input,
-        Err(err) => {
-            return ResponseJson::error(format!("Input is not valid utf8: {err}"));
-        }
+fn top_level(input: &[u8], codec: Codec) -> ResponseJson {
+    // Parse the request into our strongly-typed RequestJson struct, in whichever format the
+    // caller used.
+    let request = match codec {
+        Codec::Json => std::str::from_utf8(input)
+            .map_err(|err| format!("Input is not valid utf8: {err}"))
+            .and_then(|input| {
+                serde_json::from_str::<RequestJson>(input)
+                    .map_err(|err| format!("Input is not in the correct json format: {err}"))
+            }),
+        Codec::MessagePack => rmp_serde::from_slice::<RequestJson>(input)
+            .map_err(|err| format!("Input is not in the correct msgpack format: {err}")),
+    };
+    let mut request = match request {
+        Ok(request) => request,
+        Err(err) => return ResponseJson::error(err),
     };
 
-    // Parse the JSON into our strongly-typed RequestJson struct
-    let request = match serde_json::from_str::<RequestJson>(input) {
-        Ok(input) => input,
-        Err(err) => {
-            return ResponseJson::error(format!("Input is not in the correct json format: {err}"));
-        }
+    // Figure out which rows we're evaluating the expression against. Most callers send a single
+    // `value`, but a caller that needs to evaluate the same expression against many records can
+    // send `values` instead so that the expression is only parsed once.
+    let rows = match request.rows() {
+        Ok(rows) => rows,
+        Err(err) => return ResponseJson::error(err),
     };
 
     // And then do the real work
     match super::evaluate_expression(
         request.expression,
-        request.value,
-        request.previous_value,
+        rows,
+        request.functions,
+        request.timezone,
         request.now,
         request.date,
     ) {
-        Ok(data) => ResponseJson::success(data),
+        Ok(results) => ResponseJson::success(
+            results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(data) => RowResponse::success(data),
+                    Err(err) => RowResponse::error(err),
+                })
+                .collect(),
+        ),
         Err(err) => ResponseJson::error(err),
     }
 }
@@ -72,44 +100,245 @@ struct RequestJson {
     /// The RCP19 expression
     expression: String,
     /// JSON representing the data to be evaluated
-    value: serde_json::Value,
+    ///
+    /// Mutually exclusive with `values`; exactly one of the two must be provided. Because this is
+    /// `Option<Value>`, an explicit `"value": null` is indistinguishable from an absent `value` key
+    /// and is rejected the same way; to evaluate against a JSON `null` record, send a single-element
+    /// `values` array instead (`"values": [null]`), whose entries are never treated as absent.
+    value: Option<serde_json::Value>,
     /// JSON representing the data as it was previously, used in expressions like `[LAST FieldName]`
+    ///
+    /// Only meaningful alongside `value`.
     #[serde(rename = "previousValue")]
     previous_value: Option<serde_json::Value>,
+    /// Many records to evaluate the expression against in a single call, so that the expression
+    /// only has to be parsed once for the whole batch.
+    ///
+    /// Mutually exclusive with `value`; exactly one of the two must be provided.
+    values: Option<Vec<serde_json::Value>>,
+    /// The previous values matching each entry in `values`, used in expressions like
+    /// `[LAST FieldName]`.
+    ///
+    /// When provided, must have the same number of entries as `values`. A row with no previous
+    /// value can use `null`.
+    #[serde(rename = "previousValues")]
+    previous_values: Option<Vec<Option<serde_json::Value>>>,
+    /// Names of host-supplied functions to register with the engine, in addition to the built-in
+    /// `NOW`/`TODAY`.
+    ///
+    /// Each name must have a matching implementation on the Javascript side; when the expression
+    /// calls it, the wasm module calls back into Javascript via [call_host_function] to get the
+    /// result.
+    #[serde(default)]
+    functions: Vec<String>,
     /// The current timestamp, in UTC
     ///
     /// Wasm is completely sandboxed, which means it doesn't have a way to even get the current time
     /// from the environment. So the current time must be sent in.
     now: DateTime<FixedOffset>,
+    /// An IANA timezone name (e.g. `"America/Chicago"`) to derive `.NOW.`/`.TODAY.` from.
+    ///
+    /// When present, this is used instead of `date` to work out what `.TODAY.` means, which
+    /// avoids off-by-one-day bugs when UTC and local dates differ.
+    timezone: Option<String>,
     /// The current date, in the local timezone
     ///
     /// Wasm is completely sandboxed, which means it doesn't have a way to even get the current date
-    /// from the environment. So the current date must be sent in.
+    /// from the environment. Before `timezone` existed, this had to be sent on every request so
+    /// `.TODAY.` had a date to use.
     ///
-    /// This can't be derived from `now` without knowing the timezone. Instead of dealing with
-    /// timezones in the wasm module, we'll just pass in the current date in local time and let the
-    /// Javascript side deal with timezones.
-    date: NaiveDate,
+    /// Now that `timezone` exists, this is only used as a fallback when `timezone` isn't supplied,
+    /// so it's optional; a request with neither `timezone` nor `date` fails with an error, since
+    /// there'd be no way to know what day `.TODAY.` means.
+    date: Option<NaiveDate>,
+}
+
+impl RequestJson {
+    /// Turn the `value`/`values` (and matching `previousValue`/`previousValues`) fields into the
+    /// list of rows to evaluate the expression against.
+    fn rows(&mut self) -> Result<Vec<super::Row>, String> {
+        match (self.value.take(), self.values.take()) {
+            (Some(value), None) => Ok(vec![super::Row {
+                value,
+                previous_value: self.previous_value.take(),
+            }]),
+            (None, Some(values)) => {
+                let previous_values = self.previous_values.take();
+                if let Some(previous_values) = &previous_values {
+                    if previous_values.len() != values.len() {
+                        return Err(format!(
+                            "`previousValues` has {} entries but `values` has {}",
+                            previous_values.len(),
+                            values.len()
+                        ));
+                    }
+                }
+                let mut previous_values = previous_values.unwrap_or_default().into_iter();
+                Ok(values
+                    .into_iter()
+                    .map(|value| super::Row {
+                        value,
+                        previous_value: previous_values.next().flatten(),
+                    })
+                    .collect())
+            }
+            (Some(_), Some(_)) => {
+                Err("Only one of `value` or `values` may be provided".to_string())
+            }
+            (None, None) => Err("Either `value` or `values` must be provided".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        value: Option<serde_json::Value>,
+        values: Option<Vec<serde_json::Value>>,
+    ) -> RequestJson {
+        RequestJson {
+            expression: "Field".to_string(),
+            value,
+            previous_value: None,
+            values,
+            previous_values: None,
+            functions: Vec::new(),
+            now: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+            timezone: None,
+            date: Some(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()),
+        }
+    }
+
+    #[test]
+    fn rows_from_single_value() {
+        let mut request = request(Some(serde_json::json!({"a": 1})), None);
+        let rows = request.rows().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].value, serde_json::json!({"a": 1}));
+        assert_eq!(rows[0].previous_value, None);
+    }
+
+    #[test]
+    fn rows_from_batch_values_pairs_previous_values_by_index() {
+        let mut request = request(None, Some(vec![serde_json::json!(1), serde_json::json!(2)]));
+        request.previous_values = Some(vec![Some(serde_json::json!(0)), None]);
+        let rows = request.rows().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].previous_value, Some(serde_json::json!(0)));
+        assert_eq!(rows[1].previous_value, None);
+    }
+
+    #[test]
+    fn rows_rejects_both_value_and_values() {
+        let mut request = request(Some(serde_json::json!(1)), Some(vec![serde_json::json!(2)]));
+        assert!(request.rows().is_err());
+    }
+
+    #[test]
+    fn rows_rejects_neither_value_nor_values() {
+        let mut request = request(None, None);
+        assert!(request.rows().is_err());
+    }
+
+    #[test]
+    fn rows_rejects_mismatched_previous_values_length() {
+        let mut request = request(None, Some(vec![serde_json::json!(1), serde_json::json!(2)]));
+        request.previous_values = Some(vec![Some(serde_json::json!(0))]);
+        assert!(request.rows().is_err());
+    }
+
+    fn compile_field(name: &str) -> u32 {
+        let json = serde_json::json!({"expression": name, "functions": []}).to_string();
+        compile_top_level(json.as_bytes()).unwrap()
+    }
+
+    fn evaluate_handle(handle: u32, value: serde_json::Value) -> ResponseJson {
+        let json = serde_json::json!({
+            "value": value,
+            "now": "2024-01-01T00:00:00Z",
+            "date": "2024-01-01",
+        })
+        .to_string();
+        evaluate_compiled_top_level(handle, json.as_bytes())
+    }
+
+    #[test]
+    fn release_reuses_the_slot_instead_of_leaking_it() {
+        let first = compile_field("A");
+        release(first);
+        let second = compile_field("B");
+
+        let (first_index, _) = unpack_handle(first);
+        let (second_index, _) = unpack_handle(second);
+        assert_eq!(first_index, second_index, "the freed slot should be reused");
+        assert_ne!(first, second, "the reused handle must carry a new generation");
+
+        release(second);
+    }
+
+    #[test]
+    fn a_handle_from_before_release_keeps_failing_after_the_slot_is_reused() {
+        let first = compile_field("A");
+        release(first);
+        let second = compile_field("B");
+
+        let stale = evaluate_handle(first, serde_json::json!({"A": 1, "B": 2}));
+        assert!(stale.error.is_some());
+
+        let fresh = evaluate_handle(second, serde_json::json!({"A": 1, "B": 2}));
+        assert_eq!(fresh.results.unwrap()[0].data, Some(serde_json::json!(2)));
+
+        release(second);
+    }
 }
 
 /// The definition of the JSON blob that we send back to the Javascript
 #[derive(Serialize)]
 struct ResponseJson {
-    /// If the expression succeeded, the JSON data that the expression produced
-    data: Option<serde_json::Value>,
-    /// If the expression failed, the error string to return
+    /// If the request as a whole failed (bad JSON, or the expression itself failed to parse), the
+    /// error string to return
     error: Option<String>,
+    /// If the request was well-formed, one result per row, in the same order as the input
+    results: Option<Vec<RowResponse>>,
 }
 
 impl ResponseJson {
-    /// Create a ResponseJson with only the `data` field populated
+    /// Create a ResponseJson with only the `results` field populated
+    pub fn success(results: Vec<RowResponse>) -> Self {
+        Self {
+            error: None,
+            results: Some(results),
+        }
+    }
+    /// Create a ResponseJson with only the `error` field populated
+    pub fn error(string: String) -> Self {
+        Self {
+            error: Some(string),
+            results: None,
+        }
+    }
+}
+
+/// The result of evaluating the expression against a single row
+#[derive(Serialize)]
+struct RowResponse {
+    /// If the expression succeeded for this row, the JSON data that the expression produced
+    data: Option<serde_json::Value>,
+    /// If the expression failed for this row, the error string to return
+    error: Option<String>,
+}
+
+impl RowResponse {
+    /// Create a RowResponse with only the `data` field populated
     pub fn success(data: serde_json::Value) -> Self {
         Self {
             data: Some(data),
             error: None,
         }
     }
-    /// Create a ResponseJson with only the `error` field populated
+    /// Create a RowResponse with only the `error` field populated
     pub fn error(string: String) -> Self {
         Self {
             data: None,
@@ -129,6 +358,37 @@ unsafe extern "C" {
     /// The WebAssembly will only call this once, always at the end of its execution, and the
     /// Javascript is expected to pull the data out of memory immediately upon handling this call.
     unsafe fn output(str_start: *const u8, str_len: usize);
+
+    /// The function to call when the WebAssembly module needs to evaluate a host-supplied
+    /// function by name, passing it a JSON array of arguments.
+    ///
+    /// This doesn't return a value directly, because wasm can't return multiple values any more
+    /// than it could for [output]. Instead, while it is still handling this call, Javascript is
+    /// expected to call the exported [set_host_function_result] with the JSON-encoded result
+    /// before returning.
+    #[cfg(not(all(test, not(target_arch = "wasm32"))))]
+    unsafe fn call_host_function(
+        name_ptr: *const u8,
+        name_len: usize,
+        args_json_ptr: *const u8,
+        args_json_len: usize,
+    );
+}
+
+/// A native stand-in for the `call_host_function` import above, so that `cargo test` (which
+/// always builds for the host, not `wasm32`, even for this crate) has something to link against.
+/// Building a [super::Compiled] with host-supplied `functions` pulls in [super::HostFunction]'s
+/// vtable, and so this symbol, even when nothing ever calls it; none of this crate's tests
+/// register any `functions`, so it's never actually invoked.
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn call_host_function(
+    _name_ptr: *const u8,
+    _name_len: usize,
+    _args_json_ptr: *const u8,
+    _args_json_len: usize,
+) {
+    unreachable!("no test registers a host-supplied function")
 }
 
 /// Run the evaluation!
@@ -143,15 +403,36 @@ pub extern "C" fn run(ptr: *mut u8, len: usize) {
     let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
 
     // Do the actual work
-    let response = top_level(slice);
+    let response = top_level(slice, Codec::Json);
 
-    // Now that we have a response, we need to do the whole wasm->javascript dance in reverse.
-    // Create a string with the JSON payload we're going to return.
-    let string = serde_json::to_string(&response).unwrap();
+    respond(&response, Codec::Json);
+}
+
+/// Same as [run], but both the request and the response are encoded as MessagePack instead of
+/// JSON text.
+///
+/// For large `value` documents this shrinks payloads and skips JSON text parsing entirely; the
+/// `serde_json::Value` fields on [RequestJson]/[ResponseJson] deserialize from MessagePack just as
+/// well as they do from JSON.
+#[unsafe(no_mangle)]
+pub extern "C" fn run_msgpack(ptr: *mut u8, len: usize) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
+
+    let response = top_level(slice, Codec::MessagePack);
+
+    respond(&response, Codec::MessagePack);
+}
+
+/// Encode `response` with `codec` and hand it back to Javascript through [output].
+fn respond(response: &ResponseJson, codec: Codec) {
     // Because Rust doesn't allow returning both a pointer and a length (multi-valued returns) from
     // Webassembly, we're instead going to call an imported function with the pointer and the length
     // and expect the Javascript side to give us this function.
-    unsafe { output(string.as_ptr(), string.len()) };
+    let bytes = match codec {
+        Codec::Json => serde_json::to_vec(response).unwrap(),
+        Codec::MessagePack => rmp_serde::to_vec(response).unwrap(),
+    };
+    unsafe { output(bytes.as_ptr(), bytes.len()) };
 }
 
 /// Create space to put a string.
@@ -170,7 +451,339 @@ pub extern "C" fn alloc(len: usize) -> *mut u8 {
 ///
 /// The Javascript side should call this after calling [run] when the input blob is no longer
 /// needed.
-#[unsafe(no_mangle)]
+///
+/// Only exported under its literal C name on `wasm32`: natively (e.g. under `cargo test`, which
+/// always builds for the host target) a global symbol named `free` collides with libc's own
+/// `free` and silently corrupts the whole process the moment anything calls the real one.
+#[cfg_attr(target_arch = "wasm32", unsafe(no_mangle))]
 pub extern "C" fn free(ptr: *mut u8, len: usize) {
     unsafe { drop(Vec::from_raw_parts(ptr, len, len)) }
 }
+
+std::thread_local! {
+    /// Holds the JSON-encoded result of the most recent [call_host_function] call, set by
+    /// [set_host_function_result] before that import returns.
+    static HOST_FUNCTION_RESULT: std::cell::RefCell<Option<String>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Key used by a host function result to signal that the call failed, rather than returning a
+/// value: `{"__error": "<message>"}`.
+const HOST_FUNCTION_ERROR_KEY: &str = "__error";
+
+/// Called by Javascript, synchronously, while it is still servicing a [call_host_function] import
+/// call, to hand the JSON-encoded result back to the wasm module before that call returns.
+///
+/// The Javascript side writes its result into memory the same way it would for [run]'s input: call
+/// [alloc] to get a pointer, fill it with the UTF-8 encoded JSON, and pass that pointer and length
+/// here. This function takes ownership of the buffer, so Javascript must not call [free] on it.
+#[unsafe(no_mangle)]
+pub extern "C" fn set_host_function_result(ptr: *mut u8, len: usize) {
+    let bytes = unsafe { Vec::from_raw_parts(ptr, len, len) };
+    let string = String::from_utf8_lossy(&bytes).into_owned();
+    HOST_FUNCTION_RESULT.with(|cell| *cell.borrow_mut() = Some(string));
+}
+
+/// Copy `bytes` into a freshly [alloc]ed buffer, the same way Javascript would write a string into
+/// wasm memory, and return its pointer and length.
+fn copy_to_new_buffer(bytes: &[u8]) -> (*mut u8, usize) {
+    let ptr = alloc(bytes.len());
+    unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+    (ptr, bytes.len())
+}
+
+/// Ask Javascript to evaluate the named host function against the given JSON-encoded argument
+/// array, and return its JSON result.
+///
+/// A result of `{"__error": "<message>"}` is treated as a failure and turned into `Err`.
+pub(crate) fn invoke_host_function(
+    name: &str,
+    args_json: &str,
+) -> Result<serde_json::Value, String> {
+    let (name_ptr, name_len) = copy_to_new_buffer(name.as_bytes());
+    let (args_ptr, args_len) = copy_to_new_buffer(args_json.as_bytes());
+
+    unsafe { call_host_function(name_ptr, name_len, args_ptr, args_len) };
+    free(name_ptr, name_len);
+    free(args_ptr, args_len);
+
+    let result = HOST_FUNCTION_RESULT.with(|cell| cell.borrow_mut().take());
+    let result = result.ok_or_else(|| {
+        format!("Host function `{name}` did not provide a result before returning")
+    })?;
+
+    let value: serde_json::Value = serde_json::from_str(&result)
+        .map_err(|err| format!("Host function `{name}` returned invalid json: {err}"))?;
+
+    if let Some(error) = value
+        .as_object()
+        .filter(|object| object.len() == 1)
+        .and_then(|object| object.get(HOST_FUNCTION_ERROR_KEY))
+        .and_then(|error| error.as_str())
+    {
+        return Err(error.to_string());
+    }
+
+    Ok(value)
+}
+
+/// Handle returned by [compile] when the expression failed to parse. Passing it to
+/// [evaluate_compiled] or [release] is a no-op/error rather than a panic.
+const INVALID_HANDLE: u32 = u32::MAX;
+
+/// A slot in [COMPILED]: the compiled expression, if the slot is currently occupied, plus a
+/// generation counter bumped every time [release] frees it. The handle [compile] hands back packs
+/// this generation alongside the slot index (see [pack_handle]/[unpack_handle]), so a handle
+/// obtained before a [release] keeps failing with "not a valid compiled expression handle" instead
+/// of silently aliasing whatever a later [compile] reuses the slot for.
+#[derive(Default)]
+struct CompiledSlot {
+    compiled: Option<super::Compiled>,
+    generation: u16,
+}
+
+std::thread_local! {
+    /// Slab of compiled expressions. [release] frees a slot for reuse (pushing its index onto
+    /// [FREE_SLOTS]) rather than leaking it forever, which matters for callers that re-[compile]
+    /// on every keystroke.
+    static COMPILED: std::cell::RefCell<Vec<CompiledSlot>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+    /// Indices into [COMPILED] that [release] has freed and [compile] may reuse.
+    static FREE_SLOTS: std::cell::RefCell<Vec<usize>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Combine a slot index and its generation into the handle [compile] hands back.
+fn pack_handle(index: usize, generation: u16) -> u32 {
+    (u32::from(generation) << 16) | (index as u32 & 0xFFFF)
+}
+
+/// Split a handle back into the slot index and generation it was issued for.
+fn unpack_handle(handle: u32) -> (usize, u16) {
+    ((handle & 0xFFFF) as usize, (handle >> 16) as u16)
+}
+
+/// Report an error the same way [run] would, for exports like [compile] whose return value is a
+/// plain handle and so can't carry a `ResponseJson` directly.
+fn report_error(message: String) {
+    respond(&ResponseJson::error(message), Codec::Json);
+}
+
+/// The definition of the JSON blob [compile] accepts.
+#[derive(Deserialize)]
+struct CompileRequestJson {
+    /// The RCP19 expression
+    expression: String,
+    /// Names of host-supplied functions to register with the engine, in addition to the built-in
+    /// `NOW`/`TODAY`; see [RequestJson::functions].
+    #[serde(default)]
+    functions: Vec<String>,
+}
+
+/// Parse the expression once and cache it (and its [Engine][rets_expression::Engine]) so that
+/// [evaluate_compiled] can evaluate it repeatedly without re-parsing, which matters for callers
+/// that validate the same expression against user input on every keystroke.
+///
+/// The input is a pointer and length to a JSON blob of `{expression, functions}`, written into
+/// memory the same way as [run]'s input. Returns [INVALID_HANDLE] and reports a structured error
+/// through [output] if the input isn't valid JSON or the expression fails to parse.
+#[unsafe(no_mangle)]
+pub extern "C" fn compile(ptr: *mut u8, len: usize) -> u32 {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
+    match compile_top_level(slice) {
+        Ok(handle) => handle,
+        Err(err) => {
+            report_error(err);
+            INVALID_HANDLE
+        }
+    }
+}
+
+fn compile_top_level(input: &[u8]) -> Result<u32, String> {
+    let request = std::str::from_utf8(input)
+        .map_err(|err| format!("Input is not valid utf8: {err}"))
+        .and_then(|input| {
+            serde_json::from_str::<CompileRequestJson>(input)
+                .map_err(|err| format!("Input is not in the correct json format: {err}"))
+        })?;
+
+    let compiled = super::compile_expression(request.expression, request.functions)?;
+
+    Ok(COMPILED.with(|cell| {
+        let mut slots = cell.borrow_mut();
+        if let Some(index) = FREE_SLOTS.with(|free| free.borrow_mut().pop()) {
+            let slot = &mut slots[index];
+            slot.compiled = Some(compiled);
+            pack_handle(index, slot.generation)
+        } else {
+            let index = slots.len();
+            slots.push(CompiledSlot {
+                compiled: Some(compiled),
+                generation: 0,
+            });
+            pack_handle(index, 0)
+        }
+    }))
+}
+
+/// The definition of the JSON blob [evaluate_compiled] accepts: just the per-row data, since the
+/// expression itself was already parsed by [compile].
+#[derive(Deserialize)]
+struct CompiledRequestJson {
+    /// JSON representing the data to be evaluated
+    value: serde_json::Value,
+    /// JSON representing the data as it was previously, used in expressions like `[LAST FieldName]`
+    #[serde(rename = "previousValue")]
+    previous_value: Option<serde_json::Value>,
+    /// An IANA timezone name to derive `.NOW.`/`.TODAY.` from; see [RequestJson::timezone].
+    timezone: Option<String>,
+    /// The current timestamp, in UTC
+    now: DateTime<FixedOffset>,
+    /// The current date, in the local timezone; see [RequestJson::date]. Required when `timezone`
+    /// isn't supplied.
+    date: Option<NaiveDate>,
+}
+
+/// Evaluate the expression cached at `handle` (see [compile]) against a single row.
+///
+/// The input is a pointer and length to a JSON blob of just
+/// `{value, previousValue, timezone, now, date}`;
+/// unlike [run], there's no `expression` field to parse on every call. The result is reported
+/// through [output], the same as [run].
+#[unsafe(no_mangle)]
+pub extern "C" fn evaluate_compiled(handle: u32, ptr: *mut u8, len: usize) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
+    let response = evaluate_compiled_top_level(handle, slice);
+    respond(&response, Codec::Json);
+}
+
+fn evaluate_compiled_top_level(handle: u32, input: &[u8]) -> ResponseJson {
+    let input = match std::str::from_utf8(input) {
+        Ok(input) => input,
+        Err(err) => return ResponseJson::error(format!("Input is not valid utf8: {err}")),
+    };
+
+    let request = match serde_json::from_str::<CompiledRequestJson>(input) {
+        Ok(request) => request,
+        Err(err) => {
+            return ResponseJson::error(format!("Input is not in the correct json format: {err}"));
+        }
+    };
+
+    let (index, generation) = unpack_handle(handle);
+    let result = COMPILED.with(|cell| {
+        let slots = cell.borrow();
+        match slots.get(index) {
+            Some(slot) if slot.generation == generation => match &slot.compiled {
+                Some(compiled) => super::evaluate_compiled(
+                    compiled,
+                    request.value,
+                    request.previous_value,
+                    request.timezone,
+                    request.now,
+                    request.date,
+                ),
+                None => Err(format!("`{handle}` is not a valid compiled expression handle")),
+            },
+            _ => Err(format!("`{handle}` is not a valid compiled expression handle")),
+        }
+    });
+
+    match result {
+        Ok(data) => ResponseJson::success(vec![RowResponse::success(data)]),
+        Err(err) => ResponseJson::error(err),
+    }
+}
+
+/// Free the compiled expression stored at `handle`, making its slot available for a later
+/// [compile] call to reuse. That later [compile] gets a new handle whose generation no longer
+/// matches `handle`, so `handle` keeps failing with "not a valid compiled expression handle"
+/// afterward instead of silently aliasing whatever gets compiled into the reused slot. Releasing
+/// an already-released or invalid handle is a no-op.
+#[unsafe(no_mangle)]
+pub extern "C" fn release(handle: u32) {
+    let (index, generation) = unpack_handle(handle);
+    COMPILED.with(|cell| {
+        let mut slots = cell.borrow_mut();
+        let Some(slot) = slots.get_mut(index) else {
+            return;
+        };
+        if slot.generation != generation || slot.compiled.is_none() {
+            return;
+        }
+
+        slot.compiled = None;
+        let next_generation = slot.generation.wrapping_add(1);
+        slot.generation = next_generation;
+        // If the generation counter just wrapped back to 0, a handle from this slot's very first
+        // generation would also read as valid again. Retire the slot instead of risking that ABA
+        // aliasing; it leaks one slot, but only after 65536 compile/release cycles reuse it.
+        if next_generation != 0 {
+            FREE_SLOTS.with(|free| free.borrow_mut().push(index));
+        }
+    });
+}
+
+/// The definition of the JSON blob that [analyze] sends back to Javascript.
+#[derive(Serialize)]
+struct AnalyzeResponseJson {
+    /// If the expression failed to parse, the error string to return; the same structured error
+    /// [run] would give for the same expression.
+    error: Option<String>,
+    /// The field paths the expression reads
+    fields: Option<Vec<String>>,
+    /// Whether the expression references `[LAST ...]`, and so needs `previousValue` supplied
+    #[serde(rename = "usesPrevious")]
+    uses_previous: Option<bool>,
+    /// Whether the expression references `.NOW.`
+    #[serde(rename = "usesNow")]
+    uses_now: Option<bool>,
+    /// Whether the expression references `.TODAY.`
+    #[serde(rename = "usesToday")]
+    uses_today: Option<bool>,
+}
+
+impl AnalyzeResponseJson {
+    /// Create an AnalyzeResponseJson with only the success fields populated
+    pub fn success(capabilities: super::Capabilities) -> Self {
+        Self {
+            error: None,
+            fields: Some(capabilities.fields),
+            uses_previous: Some(capabilities.uses_previous),
+            uses_now: Some(capabilities.uses_now),
+            uses_today: Some(capabilities.uses_today),
+        }
+    }
+    /// Create an AnalyzeResponseJson with only the `error` field populated
+    pub fn error(string: String) -> Self {
+        Self {
+            error: Some(string),
+            fields: None,
+            uses_previous: None,
+            uses_now: None,
+            uses_today: None,
+        }
+    }
+}
+
+/// Parse an expression and report the fields and intrinsics it depends on, without evaluating it
+/// against any data.
+///
+/// The input is a pointer and length to the raw expression string, not a JSON blob as [compile]
+/// takes. The result (or a structured parse error, the same as [run] would give) is reported
+/// through [output] as JSON.
+#[unsafe(no_mangle)]
+pub extern "C" fn analyze(ptr: *mut u8, len: usize) {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
+
+    let response = match std::str::from_utf8(slice) {
+        Ok(expression) => match super::analyze_expression(expression.to_string()) {
+            Ok(capabilities) => AnalyzeResponseJson::success(capabilities),
+            Err(err) => AnalyzeResponseJson::error(err),
+        },
+        Err(err) => AnalyzeResponseJson::error(format!("Input is not valid utf8: {err}")),
+    };
+
+    let string = serde_json::to_string(&response).unwrap();
+    unsafe { output(string.as_ptr(), string.len()) };
+}